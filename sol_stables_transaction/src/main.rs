@@ -2,36 +2,313 @@ use solana_program::{
     account_info::{next_account_info, AccountInfo},
     entrypoint,
     entrypoint::ProgramResult,
+    hash::{hash, Hash},
     pubkey::Pubkey,
     program_error::ProgramError,
     msg,
     program::{invoke, invoke_signed},
+    program_pack::Pack,
+    rent::Rent,
     system_instruction,
+    sysvar::{clock::Clock, Sysvar},
 };
 
+use borsh::{BorshDeserialize, BorshSerialize};
 use spl_token::instruction as token_instruction;
 
+/// Seeds used to derive the escrow state PDA for a given seller/buyer pair.
+const ESCROW_SEED: &[u8] = b"escrow";
+
+/// Seeds used to derive the vault authority PDA that owns escrowed funds.
+const VAULT_SEED: &[u8] = b"vault";
+
 // Define the struct for the contract data
+#[derive(BorshSerialize, BorshDeserialize)]
 struct ExchangeContract {
+    /// Set to `true` once the state account has been initialized. A zeroed
+    /// account (freshly created) deserializes with this flag cleared, which is
+    /// how `load` distinguishes a live escrow from an empty one.
+    is_initialized: bool,
     seller_pubkey: Pubkey,
     buyer_pubkey: Pubkey,
     price: u64,
     solana_amount: u64,
     stablecoin_type: Stablecoin,
-    deadline: u64,
+    /// Mint the buyer's stablecoin account must hold, configured at init so a
+    /// worthless counterfeit token cannot be passed off as USDT/USDC.
+    stablecoin_mint: Pubkey,
+    /// Decimals of `stablecoin_mint`, enforced by `transfer_checked`.
+    stablecoin_decimals: u8,
+    /// When set, the seller's side is native SOL held as raw lamports in the
+    /// vault rather than a wrapped-SOL SPL token account.
+    is_native: bool,
+    /// Absolute unix timestamp after which the escrow may be refunded.
+    deadline: i64,
+    /// Optional predicate that must hold before an exchange can settle.
+    condition: Option<Condition>,
+    /// Bump for the `[b"vault", escrow_pda]` authority that owns the escrowed
+    /// token accounts. Stored at init so releases can `invoke_signed` for it.
+    vault_bump: u8,
+    /// Cumulative SOL deposited by the seller, capped at `solana_amount`.
+    solana_deposited_amount: u64,
+    /// Cumulative stablecoin deposited by the buyer, capped at `price`.
+    stablecoin_deposited_amount: u64,
+    /// SOL already released out of the vault: to the buyer by partial fills,
+    /// or back to the seller by a cancel or refund.
+    solana_settled_amount: u64,
+    /// Stablecoin already released out of the vault: to the seller by partial
+    /// fills, or back to the buyer by a cancel or refund.
+    stablecoin_settled_amount: u64,
+    exchange_completed: bool,
+}
+
+impl ExchangeContract {
+    /// Load the contract from a program-owned account, rejecting accounts that
+    /// belong to another program or that have never been initialized.
+    fn load(program_id: &Pubkey, account: &AccountInfo) -> Result<Self, ProgramError> {
+        if account.owner != program_id {
+            msg!("State account is not owned by this program");
+            return Err(ProgramError::IllegalOwner);
+        }
+        let contract = ExchangeContract::try_from_slice(&account.data.borrow())
+            .map_err(|_| ProgramError::InvalidAccountData)?;
+        if !contract.is_initialized {
+            msg!("Escrow account is not initialized");
+            return Err(ProgramError::UninitializedAccount);
+        }
+        Ok(contract)
+    }
+
+    /// Serialize the contract back into its state account.
+    fn save(&self, account: &AccountInfo) -> ProgramResult {
+        self.serialize(&mut &mut account.data.borrow_mut()[..])
+            .map_err(|_| ProgramError::InvalidAccountData.into())
+    }
+
+    /// SOL currently escrowed and not yet matched against the counterparty.
+    fn solana_escrowed(&self) -> u64 {
+        self.solana_deposited_amount - self.solana_settled_amount
+    }
+
+    /// Stablecoin currently escrowed and not yet matched against the counterparty.
+    fn stablecoin_escrowed(&self) -> u64 {
+        self.stablecoin_deposited_amount - self.stablecoin_settled_amount
+    }
 }
 
 // Define stablecoin types
+#[derive(BorshSerialize, BorshDeserialize)]
 enum Stablecoin {
     USDT,
     USDC,
 }
 
+/// Optional predicate, modelled on the Budget program's witness mechanism,
+/// that must hold before an exchange is allowed to settle. It lets an
+/// off-chain oracle authorize settlement by flipping an account's state
+/// without ever taking custody of the escrowed funds.
+#[derive(BorshSerialize, BorshDeserialize)]
+enum Condition {
+    /// Satisfied once the Clock sysvar reaches the given unix timestamp.
+    Timestamp(i64),
+    /// Satisfied when the given key signs the settling transaction.
+    Signature(Pubkey),
+    /// Satisfied when the witness account is owned by `owner` and its data
+    /// hashes to `expected_hash`.
+    AccountData {
+        key: Pubkey,
+        owner: Pubkey,
+        expected_hash: Hash,
+    },
+}
+
+impl Condition {
+    /// Evaluate the predicate against the supplied accounts, returning an error
+    /// if it is not yet satisfied.
+    fn evaluate(&self, accounts: &[AccountInfo]) -> ProgramResult {
+        match self {
+            Condition::Timestamp(timestamp) => {
+                if Clock::get()?.unix_timestamp < *timestamp {
+                    msg!("Release condition not met: timestamp");
+                    return Err(ProgramError::InvalidAccountData);
+                }
+            }
+            Condition::Signature(key) => {
+                let signed = accounts.iter().any(|a| a.key == key && a.is_signer);
+                if !signed {
+                    msg!("Release condition not met: missing signature");
+                    return Err(ProgramError::MissingRequiredSignature);
+                }
+            }
+            Condition::AccountData { key, owner, expected_hash } => {
+                let witness = accounts
+                    .iter()
+                    .find(|a| a.key == key)
+                    .ok_or(ProgramError::NotEnoughAccountKeys)?;
+                if witness.owner != owner {
+                    msg!("Release condition not met: witness owner mismatch");
+                    return Err(ProgramError::IllegalOwner);
+                }
+                if hash(&witness.data.borrow()) != *expected_hash {
+                    msg!("Release condition not met: witness data mismatch");
+                    return Err(ProgramError::InvalidAccountData);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
 // Entry point of the smart contract
 entrypoint!(process_instruction);
 
+/// Parsed, fully-typed instruction payloads.
+enum InstructionType {
+    /// Create the escrow state PDA and seed it with the agreed terms.
+    Initialize {
+        price: u64,
+        solana_amount: u64,
+        stablecoin_type: Stablecoin,
+        stablecoin_mint: Pubkey,
+        stablecoin_decimals: u8,
+        is_native: bool,
+        deadline: i64,
+        condition: Option<Condition>,
+    },
+    /// Deposit `amount` toward this caller's side of the escrow.
+    Deposit { amount: u64 },
+    Exchange,
+    Refund,
+    /// Reclaim this caller's still-escrowed deposit before the deadline.
+    Cancel,
+}
+
+// Function to handle the initial creation of the escrow state account
+fn handle_initialize(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    price: u64,
+    solana_amount: u64,
+    stablecoin_type: Stablecoin,
+    stablecoin_mint: Pubkey,
+    stablecoin_decimals: u8,
+    is_native: bool,
+    deadline: i64,
+    condition: Option<Condition>,
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let seller_account = next_account_info(account_info_iter)?;
+    let buyer_account = next_account_info(account_info_iter)?;
+    let escrow_account = next_account_info(account_info_iter)?;
+    let payer_account = next_account_info(account_info_iter)?;
+    let system_program = next_account_info(account_info_iter)?;
+
+    // Derive the escrow PDA and make sure the caller supplied the matching account.
+    let (escrow_pda, bump) = Pubkey::find_program_address(
+        &[ESCROW_SEED, seller_account.key.as_ref(), buyer_account.key.as_ref()],
+        program_id,
+    );
+    if escrow_pda != *escrow_account.key {
+        msg!("Supplied escrow account does not match the derived PDA");
+        return Err(ProgramError::InvalidSeeds);
+    }
+
+    // `deadline` is an absolute unix timestamp; refusing a deadline that is
+    // already in the past keeps the refund path from being immediately open.
+    let now = Clock::get()?.unix_timestamp;
+    if deadline <= now {
+        msg!("Deadline must be in the future");
+        return Err(ProgramError::InvalidInstructionData);
+    }
+
+    // Derive the vault authority that will own the escrowed token accounts and
+    // remember its bump so releases can sign for it later.
+    let (vault_authority, vault_bump) =
+        Pubkey::find_program_address(&[VAULT_SEED, escrow_pda.as_ref()], program_id);
+
+    let contract = ExchangeContract {
+        is_initialized: true,
+        seller_pubkey: *seller_account.key,
+        buyer_pubkey: *buyer_account.key,
+        price,
+        solana_amount,
+        stablecoin_type,
+        stablecoin_mint,
+        stablecoin_decimals,
+        is_native,
+        deadline,
+        condition,
+        vault_bump,
+        solana_deposited_amount: 0,
+        stablecoin_deposited_amount: 0,
+        solana_settled_amount: 0,
+        stablecoin_settled_amount: 0,
+        exchange_completed: false,
+    };
+
+    // Create the account, sized to the serialized struct and rent-exempt.
+    let space = contract.try_to_vec()?.len();
+    let rent = Rent::get()?;
+    let lamports = rent.minimum_balance(space);
+    invoke_signed(
+        &system_instruction::create_account(
+            payer_account.key,
+            escrow_account.key,
+            lamports,
+            space as u64,
+            program_id,
+        ),
+        &[payer_account.clone(), escrow_account.clone(), system_program.clone()],
+        &[&[ESCROW_SEED, seller_account.key.as_ref(), buyer_account.key.as_ref(), &[bump]]],
+    )?;
+
+    // For a native-SOL escrow the vault authority PDA itself holds the
+    // escrowed lamports, so it must be a program-owned account — otherwise the
+    // runtime forbids the program from debiting it on release. Create it here.
+    if is_native {
+        let vault_account = next_account_info(account_info_iter)?;
+        if *vault_account.key != vault_authority {
+            msg!("Supplied vault account does not match the derived PDA");
+            return Err(ProgramError::InvalidSeeds);
+        }
+        let vault_lamports = rent.minimum_balance(0);
+        invoke_signed(
+            &system_instruction::create_account(
+                payer_account.key,
+                vault_account.key,
+                vault_lamports,
+                0,
+                program_id,
+            ),
+            &[payer_account.clone(), vault_account.clone(), system_program.clone()],
+            &[&[VAULT_SEED, escrow_pda.as_ref(), &[vault_bump]]],
+        )?;
+    }
+
+    contract.save(escrow_account)?;
+
+    msg!("Escrow initialized");
+    Ok(())
+}
+
+/// Move raw lamports out of a program-owned account, guarding against an
+/// overdraw. Used for the native-SOL side where `spl_token::transfer` does not
+/// apply.
+fn move_lamports(from: &AccountInfo, to: &AccountInfo, amount: u64) -> ProgramResult {
+    let mut from_lamports = from.try_borrow_mut_lamports()?;
+    if **from_lamports < amount {
+        msg!("Insufficient funds to move lamports");
+        return Err(ProgramError::InsufficientFunds);
+    }
+    **from_lamports -= amount;
+    **to.try_borrow_mut_lamports()? += amount;
+    Ok(())
+}
+
 // Function to handle deposits
 fn handle_deposit(
+    program_id: &Pubkey,
+    escrow_key: &Pubkey,
     accounts: &[AccountInfo],
     contract: &mut ExchangeContract,
     depositor_pubkey: &Pubkey,
@@ -45,19 +322,97 @@ fn handle_deposit(
         return Err(ProgramError::InvalidAccountData);
     }
 
-    // Verify the correct amount
-    let expected_amount = if is_solana { contract.solana_amount } else { contract.price };
-    if amount != expected_amount {
-        msg!("Deposit rejected: Incorrect amount");
+    // Partial deposits are allowed; reject only an overshoot past the agreed
+    // total for this side.
+    let (running, cap) = if is_solana {
+        (contract.solana_deposited_amount, contract.solana_amount)
+    } else {
+        (contract.stablecoin_deposited_amount, contract.price)
+    };
+    let new_total = running
+        .checked_add(amount)
+        .ok_or(ProgramError::InvalidInstructionData)?;
+    if new_total > cap {
+        msg!("Deposit rejected: exceeds agreed amount");
         return Err(ProgramError::InvalidAccountData);
     }
 
-    // Update contract state accordingly
-    // Assuming the contract has fields to track whether deposits have been made
+    // Move the deposit into the program-owned vault so the running balance is
+    // backed by real custody.
+    let depositor_account = &accounts[0];
+    if is_solana && contract.is_native {
+        // Native SOL: straight from the depositor's wallet into the vault
+        // lamport account via a System-program CPI.
+        let vault_solana_account = &accounts[1];
+        assert_vault_account(program_id, escrow_key, vault_solana_account, contract.vault_bump)?;
+        let system_program = &accounts[2];
+        invoke(
+            &system_instruction::transfer(depositor_account.key, vault_solana_account.key, amount),
+            &[depositor_account.clone(), vault_solana_account.clone(), system_program.clone()],
+        )?;
+    } else if is_solana {
+        // Wrapped-SOL side: the seller signs as the authority of their own
+        // token account and transfers into the vault token account.
+        let source_account = &accounts[1];
+        let vault_token_account = &accounts[2];
+        let transfer_instruction = token_instruction::transfer(
+            &spl_token::id(),
+            source_account.key,
+            vault_token_account.key,
+            depositor_account.key,
+            &[depositor_account.key],
+            amount,
+        )?;
+        invoke(
+            &transfer_instruction,
+            &[source_account.clone(), vault_token_account.clone(), depositor_account.clone()],
+        )?;
+    } else {
+        // Stablecoin side: confirm the buyer is depositing the agreed mint and
+        // not an arbitrary look-alike token, then move it with transfer_checked
+        // so the runtime enforces the mint and decimals.
+        let source_account = &accounts[1];
+        let vault_token_account = &accounts[2];
+        let mint_account = &accounts[3];
+        if source_account.owner != &spl_token::id() {
+            msg!("Deposit rejected: stablecoin account not owned by the token program");
+            return Err(ProgramError::IllegalOwner);
+        }
+        let token_account = spl_token::state::Account::unpack(&source_account.data.borrow())?;
+        if token_account.mint != contract.stablecoin_mint {
+            msg!("Deposit rejected: unexpected stablecoin mint");
+            return Err(ProgramError::InvalidAccountData);
+        }
+        if *mint_account.key != contract.stablecoin_mint {
+            msg!("Deposit rejected: unexpected stablecoin mint account");
+            return Err(ProgramError::InvalidAccountData);
+        }
+        let transfer_instruction = token_instruction::transfer_checked(
+            &spl_token::id(),
+            source_account.key,
+            mint_account.key,
+            vault_token_account.key,
+            depositor_account.key,
+            &[depositor_account.key],
+            amount,
+            contract.stablecoin_decimals,
+        )?;
+        invoke(
+            &transfer_instruction,
+            &[
+                source_account.clone(),
+                mint_account.clone(),
+                vault_token_account.clone(),
+                depositor_account.clone(),
+            ],
+        )?;
+    }
+
+    // Update the running balance for this side.
     if is_solana {
-        contract.solana_deposited = true;
+        contract.solana_deposited_amount = new_total;
     } else {
-        contract.stablecoin_deposited = true;
+        contract.stablecoin_deposited_amount = new_total;
     }
 
     msg!("Deposit received");
@@ -65,71 +420,189 @@ fn handle_deposit(
 }
 
 
+/// Assert that `account` is the program's vault PDA for this escrow. Used to
+/// validate the native lamport vault before it is debited directly, since
+/// `move_lamports` — unlike `spl_token::transfer` — does not itself constrain
+/// the source account.
+fn assert_vault_account(
+    program_id: &Pubkey,
+    escrow_key: &Pubkey,
+    account: &AccountInfo,
+    vault_bump: u8,
+) -> ProgramResult {
+    let expected = Pubkey::create_program_address(
+        &[VAULT_SEED, escrow_key.as_ref(), &[vault_bump]],
+        program_id,
+    )
+    .map_err(|_| ProgramError::InvalidSeeds)?;
+    if expected != *account.key {
+        msg!("Vault account does not match the derived PDA");
+        return Err(ProgramError::InvalidSeeds);
+    }
+    // The native vault is debited directly, so it must belong to this program.
+    if account.owner != program_id {
+        msg!("Vault account is not owned by the program");
+        return Err(ProgramError::IllegalOwner);
+    }
+    Ok(())
+}
+
+/// Assert that `account` is an SPL token account owned by `expected_owner`.
+/// Used to pin settlement/refund destinations to the escrow's parties so a
+/// caller cannot redirect the payout to an account they control.
+fn assert_token_owner(account: &AccountInfo, expected_owner: &Pubkey) -> ProgramResult {
+    if account.owner != &spl_token::id() {
+        msg!("Destination is not a token account");
+        return Err(ProgramError::IllegalOwner);
+    }
+    let token_account = spl_token::state::Account::unpack(&account.data.borrow())?;
+    if token_account.owner != *expected_owner {
+        msg!("Destination token account not owned by the expected party");
+        return Err(ProgramError::InvalidAccountData);
+    }
+    Ok(())
+}
+
+/// Confirm `vault_authority` is the PDA derived from the escrow key and return
+/// the signer seeds used to authorize transfers out of the vault, without the
+/// bump; callers append `[vault_bump]` before calling `invoke_signed`.
+fn vault_signer_seeds<'a>(
+    program_id: &Pubkey,
+    escrow_key: &'a Pubkey,
+    vault_authority: &AccountInfo,
+    vault_bump: u8,
+) -> Result<[&'a [u8]; 2], ProgramError> {
+    let expected = Pubkey::create_program_address(
+        &[VAULT_SEED, escrow_key.as_ref(), &[vault_bump]],
+        program_id,
+    )
+    .map_err(|_| ProgramError::InvalidSeeds)?;
+    if expected != *vault_authority.key {
+        msg!("Vault authority does not match the derived PDA");
+        return Err(ProgramError::InvalidSeeds);
+    }
+    Ok([VAULT_SEED, escrow_key.as_ref()])
+}
+
 // Function to handle exchange
 fn handle_exchange(
+    program_id: &Pubkey,
+    escrow_key: &Pubkey,
     accounts: &[AccountInfo],
     contract: &mut ExchangeContract,
     token_program_id: &Pubkey,
 ) -> ProgramResult {
 
-     // Check if both parties have deposited the correct amounts
-     if !contract.solana_deposited || !contract.stablecoin_deposited {
-        msg!("Exchange cannot be executed: Both parties have not deposited");
+     // Determine how much can be matched right now at the agreed ratio. Either
+     // side may be partially funded, so we settle the proportional minimum and
+     // leave any remainder escrowed for a later fill or refund.
+    let solana_escrowed = contract.solana_escrowed();
+    let solana_backed_by_stable =
+        (contract.stablecoin_escrowed() as u128 * contract.solana_amount as u128
+            / contract.price as u128) as u64;
+    let solana_fill = solana_escrowed.min(solana_backed_by_stable);
+    let stablecoin_fill =
+        (solana_fill as u128 * contract.price as u128 / contract.solana_amount as u128) as u64;
+    if solana_fill == 0 || stablecoin_fill == 0 {
+        msg!("Exchange cannot be executed: nothing to match yet");
         return Err(ProgramError::InvalidAccountData);
     }
 
-    // Validate the seller's and buyer's accounts
-    let seller_account = &accounts[0];
-    let buyer_account = &accounts[1];
-    if *seller_account.key != contract.seller_pubkey || *buyer_account.key != contract.buyer_pubkey {
-        msg!("Exchange failed: Invalid accounts");
+    // The vault authority owns both escrowed token accounts; it — not either
+    // party — signs for the outgoing transfers.
+    let vault_authority = &accounts[0];
+    let vault_solana_account = &accounts[1];
+    let buyer_solana_account = &accounts[2];
+    let vault_stablecoin_account = &accounts[3];
+    let seller_stablecoin_account = &accounts[4];
+    let stablecoin_mint = &accounts[5];
+    if *stablecoin_mint.key != contract.stablecoin_mint {
+        msg!("Exchange failed: unexpected stablecoin mint");
         return Err(ProgramError::InvalidAccountData);
     }
 
-    let seller_solana_account = &accounts[2];
-    let buyer_stablecoin_account = &accounts[3];
-
-    // Transfer Solana from seller to buyer
-    let solana_transfer_instruction = token_instruction::transfer(
-        token_program_id,
-        seller_solana_account.key,
-        buyer_account.key, // Buyer's main account receives the Solana
-        seller_account.key, // Seller is the authority of seller's Solana account
-        &[&seller_account.key],
-        contract.solana_amount,
-    )?;
-    invoke(
-        &solana_transfer_instruction,
-        &[seller_solana_account.clone(), buyer_account.clone()],
-    )?;
+    // Pin both payout destinations to the escrow's parties so an arbitrary
+    // caller cannot point the outputs at accounts they control. Native SOL is
+    // released to the buyer's wallet directly; token sides must be token
+    // accounts owned by the respective party.
+    if contract.is_native {
+        if *buyer_solana_account.key != contract.buyer_pubkey {
+            msg!("Exchange failed: Solana destination is not the buyer");
+            return Err(ProgramError::InvalidAccountData);
+        }
+    } else {
+        assert_token_owner(buyer_solana_account, &contract.buyer_pubkey)?;
+    }
+    assert_token_owner(seller_stablecoin_account, &contract.seller_pubkey)?;
+
+    let seeds = vault_signer_seeds(program_id, escrow_key, vault_authority, contract.vault_bump)?;
+    let bump = [contract.vault_bump];
+    let signer_seeds: &[&[u8]] = &[seeds[0], seeds[1], &bump];
+
+    // Release the escrowed Solana to the buyer. Native SOL is moved as raw
+    // lamports out of the program-owned vault; wrapped SOL goes through the
+    // token program with the vault authority signing.
+    if contract.is_native {
+        assert_vault_account(program_id, escrow_key, vault_solana_account, contract.vault_bump)?;
+        move_lamports(vault_solana_account, buyer_solana_account, solana_fill)?;
+    } else {
+        let solana_transfer_instruction = token_instruction::transfer(
+            token_program_id,
+            vault_solana_account.key,
+            buyer_solana_account.key,
+            vault_authority.key,
+            &[vault_authority.key],
+            solana_fill,
+        )?;
+        invoke_signed(
+            &solana_transfer_instruction,
+            &[vault_solana_account.clone(), buyer_solana_account.clone(), vault_authority.clone()],
+            &[signer_seeds],
+        )?;
+    }
 
-    // Transfer stablecoin from buyer to seller
-    let stablecoin_transfer_instruction = token_instruction::transfer(
+    // Release the escrowed stablecoin to the seller, letting the runtime
+    // enforce the mint and decimals via transfer_checked.
+    let stablecoin_transfer_instruction = token_instruction::transfer_checked(
         token_program_id,
-        buyer_stablecoin_account.key,
-        seller_account.key, // Seller's main account receives the stablecoin
-        buyer_account.key, // Buyer is the authority of buyer's stablecoin account
-        &[&buyer_account.key],
-        contract.price,
+        vault_stablecoin_account.key,
+        stablecoin_mint.key,
+        seller_stablecoin_account.key,
+        vault_authority.key,
+        &[vault_authority.key],
+        stablecoin_fill,
+        contract.stablecoin_decimals,
     )?;
-    invoke(
+    invoke_signed(
         &stablecoin_transfer_instruction,
-        &[buyer_stablecoin_account.clone(), seller_account.clone()],
+        &[
+            vault_stablecoin_account.clone(),
+            stablecoin_mint.clone(),
+            seller_stablecoin_account.clone(),
+            vault_authority.clone(),
+        ],
+        &[signer_seeds],
     )?;
 
-    // Update contract state to indicate completion of exchange
-    contract.exchange_completed = true;
+    // Record the settled portion and mark completion once the full order fills.
+    contract.solana_settled_amount += solana_fill;
+    contract.stablecoin_settled_amount += stablecoin_fill;
+    if contract.solana_settled_amount == contract.solana_amount {
+        contract.exchange_completed = true;
+    }
 
-    msg!("Exchange executed successfully");
+    msg!("Exchange executed: settled {} SOL for {} stablecoin", solana_fill, stablecoin_fill);
     Ok(())
 }
 
 // Function to handle refund
 fn handle_refund(
+    program_id: &Pubkey,
+    escrow_key: &Pubkey,
     accounts: &[AccountInfo],
-    contract: &ExchangeContract,
+    contract: &mut ExchangeContract,
     token_program_id: &Pubkey,
-    current_time: u64,
+    current_time: i64,
 ) -> ProgramResult {
     // Check if the deadline has passed without exchange completion
     if current_time <= contract.deadline || contract.exchange_completed {
@@ -137,68 +610,186 @@ fn handle_refund(
         return Err(ProgramError::InvalidAccountData);
     }
 
-    // Assuming accounts[0] is the seller's main account
-    // Assuming accounts[1] is the buyer's main account
-    // Assuming accounts[2] is the contract's Solana holding account
-    // Assuming accounts[3] is the contract's stablecoin holding account
-    let seller_main_account = &accounts[0];
-    let buyer_main_account = &accounts[1];
-    let contract_solana_account = &accounts[2];
-    let contract_stablecoin_account = &accounts[3];
+    // accounts[0] is the vault authority that owns the escrowed token accounts
+    // accounts[1] is the vault's Solana holding account
+    // accounts[2] is the seller's main token account, or the seller's wallet
+    //             for a native-SOL escrow (refund destination)
+    // accounts[3] is the vault's stablecoin holding account
+    // accounts[4] is the buyer's main token account (refund destination)
+    // accounts[5] is the stablecoin mint
+    let vault_authority = &accounts[0];
+    let vault_solana_account = &accounts[1];
+    let seller_main_account = &accounts[2];
+    let vault_stablecoin_account = &accounts[3];
+    let buyer_main_account = &accounts[4];
+    let stablecoin_mint = &accounts[5];
+    if *stablecoin_mint.key != contract.stablecoin_mint {
+        msg!("Refund failed: unexpected stablecoin mint");
+        return Err(ProgramError::InvalidAccountData);
+    }
 
-    // Refund Solana to the seller
-    let solana_refund_instruction = token_instruction::transfer(
-        token_program_id,
-        contract_solana_account.key,
-        seller_main_account.key,
-        contract_solana_account.key, // Assuming the contract is the authority of its Solana account
-        &[&contract_solana_account.key],
-        contract.solana_amount,
-    )?;
-    invoke(
-        &solana_refund_instruction,
-        &[contract_solana_account.clone(), seller_main_account.clone()],
-    )?;
+    // Pin both refund destinations to the escrow's parties so any caller (the
+    // refund path is permissionless after the deadline) cannot drain the escrow
+    // to accounts they control.
+    if contract.is_native {
+        if *seller_main_account.key != contract.seller_pubkey {
+            msg!("Refund failed: Solana destination is not the seller");
+            return Err(ProgramError::InvalidAccountData);
+        }
+    } else {
+        assert_token_owner(seller_main_account, &contract.seller_pubkey)?;
+    }
+    assert_token_owner(buyer_main_account, &contract.buyer_pubkey)?;
+
+    // Each party is refunded whatever they still have escrowed and unmatched.
+    let solana_refund = contract.solana_escrowed();
+    let stablecoin_refund = contract.stablecoin_escrowed();
 
-    // Refund stablecoin to the buyer
-    let stablecoin_refund_instruction = token_instruction::transfer(
+    let seeds = vault_signer_seeds(program_id, escrow_key, vault_authority, contract.vault_bump)?;
+    let bump = [contract.vault_bump];
+    let signer_seeds: &[&[u8]] = &[seeds[0], seeds[1], &bump];
+
+    // Refund Solana to the seller. Native SOL is returned as raw lamports;
+    // wrapped SOL goes through the token program with the vault signing.
+    if contract.is_native {
+        assert_vault_account(program_id, escrow_key, vault_solana_account, contract.vault_bump)?;
+        move_lamports(vault_solana_account, seller_main_account, solana_refund)?;
+    } else {
+        let solana_refund_instruction = token_instruction::transfer(
+            token_program_id,
+            vault_solana_account.key,
+            seller_main_account.key,
+            vault_authority.key,
+            &[vault_authority.key],
+            solana_refund,
+        )?;
+        invoke_signed(
+            &solana_refund_instruction,
+            &[vault_solana_account.clone(), seller_main_account.clone(), vault_authority.clone()],
+            &[signer_seeds],
+        )?;
+    }
+
+    // Refund stablecoin to the buyer, signing as the vault authority and
+    // enforcing the mint and decimals via transfer_checked.
+    let stablecoin_refund_instruction = token_instruction::transfer_checked(
         token_program_id,
-        contract_stablecoin_account.key,
+        vault_stablecoin_account.key,
+        stablecoin_mint.key,
         buyer_main_account.key,
-        contract_stablecoin_account.key, // Assuming the contract is the authority of its stablecoin account
-        &[&contract_stablecoin_account.key],
-        contract.price,
+        vault_authority.key,
+        &[vault_authority.key],
+        stablecoin_refund,
+        contract.stablecoin_decimals,
     )?;
-    invoke(
+    invoke_signed(
         &stablecoin_refund_instruction,
-        &[contract_stablecoin_account.clone(), buyer_main_account.clone()],
+        &[
+            vault_stablecoin_account.clone(),
+            stablecoin_mint.clone(),
+            buyer_main_account.clone(),
+            vault_authority.clone(),
+        ],
+        &[signer_seeds],
     )?;
 
+    // The escrow is now drained on both sides.
+    contract.solana_settled_amount = contract.solana_deposited_amount;
+    contract.stablecoin_settled_amount = contract.stablecoin_deposited_amount;
+
     msg!("Refund processed");
     Ok(())
 }
 
-
-enum InstructionType {
-    Deposit,
-    Exchange,
-    Refund,
-}
-
-// Function to parse instruction data
-fn parse_instruction_data(data: &[u8]) -> Result<InstructionType, ProgramError> {
-    if data.is_empty() {
-        return Err(ProgramError::InvalidInstructionData);
+// Function to handle a pre-deadline cancellation by one party
+fn handle_cancel(
+    program_id: &Pubkey,
+    escrow_key: &Pubkey,
+    accounts: &[AccountInfo],
+    contract: &mut ExchangeContract,
+    token_program_id: &Pubkey,
+) -> ProgramResult {
+    // accounts[0] is the caller (seller or buyer) reclaiming their deposit
+    // accounts[1] is the vault authority
+    // accounts[2] is the vault holding account for the caller's side
+    // accounts[3] is the caller's destination account
+    // accounts[4] is the stablecoin mint (only used on the buyer's side)
+    let caller = &accounts[0];
+    let vault_authority = &accounts[1];
+    let vault_account = &accounts[2];
+    let destination = &accounts[3];
+
+    if !caller.is_signer {
+        msg!("Cancel rejected: caller must sign");
+        return Err(ProgramError::MissingRequiredSignature);
     }
 
-    match data[0] {
-        0 => Ok(InstructionType::Deposit),
-        1 => Ok(InstructionType::Exchange),
-        2 => Ok(InstructionType::Refund),
-        _ => Err(ProgramError::InvalidInstructionData),
+    let seeds = vault_signer_seeds(program_id, escrow_key, vault_authority, contract.vault_bump)?;
+    let bump = [contract.vault_bump];
+    let signer_seeds: &[&[u8]] = &[seeds[0], seeds[1], &bump];
+
+    if *caller.key == contract.seller_pubkey {
+        let amount = contract.solana_escrowed();
+        if amount == 0 {
+            msg!("Cancel rejected: nothing escrowed");
+            return Err(ProgramError::InvalidAccountData);
+        }
+        if contract.is_native {
+            assert_vault_account(program_id, escrow_key, vault_account, contract.vault_bump)?;
+            move_lamports(vault_account, destination, amount)?;
+        } else {
+            let instruction = token_instruction::transfer(
+                token_program_id,
+                vault_account.key,
+                destination.key,
+                vault_authority.key,
+                &[vault_authority.key],
+                amount,
+            )?;
+            invoke_signed(
+                &instruction,
+                &[vault_account.clone(), destination.clone(), vault_authority.clone()],
+                &[signer_seeds],
+            )?;
+        }
+        contract.solana_settled_amount = contract.solana_deposited_amount;
+    } else if *caller.key == contract.buyer_pubkey {
+        let amount = contract.stablecoin_escrowed();
+        if amount == 0 {
+            msg!("Cancel rejected: nothing escrowed");
+            return Err(ProgramError::InvalidAccountData);
+        }
+        let stablecoin_mint = &accounts[4];
+        if *stablecoin_mint.key != contract.stablecoin_mint {
+            msg!("Cancel failed: unexpected stablecoin mint");
+            return Err(ProgramError::InvalidAccountData);
+        }
+        let instruction = token_instruction::transfer_checked(
+            token_program_id,
+            vault_account.key,
+            stablecoin_mint.key,
+            destination.key,
+            vault_authority.key,
+            &[vault_authority.key],
+            amount,
+            contract.stablecoin_decimals,
+        )?;
+        invoke_signed(
+            &instruction,
+            &[vault_account.clone(), stablecoin_mint.clone(), destination.clone(), vault_authority.clone()],
+            &[signer_seeds],
+        )?;
+        contract.stablecoin_settled_amount = contract.stablecoin_deposited_amount;
+    } else {
+        msg!("Cancel rejected: caller is not a party to this escrow");
+        return Err(ProgramError::InvalidAccountData);
     }
+
+    msg!("Partial deposit reclaimed");
+    Ok(())
 }
 
+
 // Function to validate transaction
 fn validate_transaction(
     accounts: &[AccountInfo],
@@ -206,7 +797,10 @@ fn validate_transaction(
     instruction_type: &InstructionType,
 ) -> ProgramResult {
     match instruction_type {
-        InstructionType::Deposit => {
+        // Initialization happens before any contract exists, so it is validated
+        // inside `handle_initialize` rather than here.
+        InstructionType::Initialize { .. } => {}
+        InstructionType::Deposit { .. } => {
             // Validate deposit
             // Assuming the first account is the depositor's account
             let depositor_account = &accounts[0];
@@ -216,60 +810,192 @@ fn validate_transaction(
                 msg!("Invalid depositor for the deposit transaction");
                 return Err(ProgramError::InvalidAccountData);
             }
-
-            // Further checks can include verifying the deposit amount, etc.
-        },
+        }
         InstructionType::Exchange => {
             // Validate exchange
-            // Ensure both parties have deposited
-            if !contract.solana_deposited || !contract.stablecoin_deposited {
+            // Ensure both sides have something escrowed to match against.
+            if contract.solana_escrowed() == 0 || contract.stablecoin_escrowed() == 0 {
                 msg!("Cannot execute exchange: Deposits not completed");
                 return Err(ProgramError::InvalidAccountData);
             }
 
-            // Further validation can include checking the current state of the contract, etc.
-        },
+            // Gate settlement on the optional release condition, if any.
+            if let Some(condition) = &contract.condition {
+                condition.evaluate(accounts)?;
+            }
+        }
+        InstructionType::Cancel => {
+            // Cancellation is only allowed before the deadline and before the
+            // exchange has fully settled.
+            if contract.exchange_completed {
+                msg!("Cannot cancel: exchange already completed");
+                return Err(ProgramError::InvalidAccountData);
+            }
+            if Clock::get()?.unix_timestamp > contract.deadline {
+                msg!("Cannot cancel: deadline has passed, use refund");
+                return Err(ProgramError::InvalidAccountData);
+            }
+        }
         InstructionType::Refund => {
             // Validate refund
             // Check if the deadline has passed and exchange has not been completed
-            let current_time = ...; // Obtain the current time
+            let current_time = Clock::get()?.unix_timestamp;
             if current_time <= contract.deadline || contract.exchange_completed {
                 msg!("Refund conditions not met");
                 return Err(ProgramError::InvalidAccountData);
             }
-
-            // Further checks can include verifying the party requesting the refund, etc.
-        },
+        }
     }
 
     msg!("Transaction validated");
     Ok(())
 }
 
+// Function to parse instruction data into a fully-typed payload
+fn parse_instruction_data(data: &[u8]) -> Result<InstructionType, ProgramError> {
+    let (tag, rest) = data.split_first().ok_or(ProgramError::InvalidInstructionData)?;
+
+    match tag {
+        0 => {
+            // Initialize: price | solana_amount | stablecoin_type | stablecoin_mint |
+            // stablecoin_decimals | is_native | deadline | condition
+            let mut rest = rest;
+            let price = read_u64(&mut rest)?;
+            let solana_amount = read_u64(&mut rest)?;
+            let stablecoin_type = match read_u8(&mut rest)? {
+                0 => Stablecoin::USDT,
+                1 => Stablecoin::USDC,
+                _ => return Err(ProgramError::InvalidInstructionData),
+            };
+            let stablecoin_mint = read_pubkey(&mut rest)?;
+            let stablecoin_decimals = read_u8(&mut rest)?;
+            let is_native = read_u8(&mut rest)? != 0;
+            let deadline = read_i64(&mut rest)?;
+            // The optional release condition is Borsh-encoded in the tail.
+            let condition = Option::<Condition>::try_from_slice(rest)
+                .map_err(|_| ProgramError::InvalidInstructionData)?;
+            Ok(InstructionType::Initialize {
+                price,
+                solana_amount,
+                stablecoin_type,
+                stablecoin_mint,
+                stablecoin_decimals,
+                is_native,
+                deadline,
+                condition,
+            })
+        }
+        1 => {
+            let mut rest = rest;
+            let amount = read_u64(&mut rest)?;
+            Ok(InstructionType::Deposit { amount })
+        }
+        2 => Ok(InstructionType::Exchange),
+        3 => Ok(InstructionType::Refund),
+        4 => Ok(InstructionType::Cancel),
+        _ => Err(ProgramError::InvalidInstructionData),
+    }
+}
+
+/// Pop a little-endian `u64` off the front of the instruction payload.
+fn read_u64(data: &mut &[u8]) -> Result<u64, ProgramError> {
+    if data.len() < 8 {
+        return Err(ProgramError::InvalidInstructionData);
+    }
+    let (head, tail) = data.split_at(8);
+    *data = tail;
+    Ok(u64::from_le_bytes(head.try_into().unwrap()))
+}
+
+/// Pop a little-endian `i64` off the front of the instruction payload.
+fn read_i64(data: &mut &[u8]) -> Result<i64, ProgramError> {
+    if data.len() < 8 {
+        return Err(ProgramError::InvalidInstructionData);
+    }
+    let (head, tail) = data.split_at(8);
+    *data = tail;
+    Ok(i64::from_le_bytes(head.try_into().unwrap()))
+}
+
+/// Pop a single byte off the front of the instruction payload.
+fn read_u8(data: &mut &[u8]) -> Result<u8, ProgramError> {
+    let (head, tail) = data.split_first().ok_or(ProgramError::InvalidInstructionData)?;
+    *data = tail;
+    Ok(*head)
+}
+
+/// Pop a 32-byte pubkey off the front of the instruction payload.
+fn read_pubkey(data: &mut &[u8]) -> Result<Pubkey, ProgramError> {
+    if data.len() < 32 {
+        return Err(ProgramError::InvalidInstructionData);
+    }
+    let (head, tail) = data.split_at(32);
+    *data = tail;
+    Ok(Pubkey::new_from_array(head.try_into().unwrap()))
+}
+
 // Main processing function
 fn process_instruction(
     program_id: &Pubkey,
     accounts: &[AccountInfo],
     instruction_data: &[u8],
 ) -> ProgramResult {
-    let account_info_iter = &mut accounts.iter();
-    let seller_account = next_account_info(account_info_iter)?;
-    let buyer_account = next_account_info(account_info_iter)?;
-
     // Parse the instruction data
     let instruction_type = parse_instruction_data(instruction_data)?;
 
-    // Validate the transaction
-    let contract = ExchangeContract { /* ... fill with contract data ... */ };
-    validate_transaction(accounts, &contract, &instruction_type)?;
-
-    // Call the corresponding function based on the action
-    match instruction_type {
-        InstructionType::Deposit => handle_deposit(accounts, /* ... */),
-        InstructionType::Exchange => handle_exchange(accounts, &contract),
-        InstructionType::Refund => handle_refund(accounts, &contract, /* ... */),
+    // Initialize is the only instruction that creates rather than loads state.
+    if let InstructionType::Initialize {
+        price,
+        solana_amount,
+        stablecoin_type,
+        stablecoin_mint,
+        stablecoin_decimals,
+        is_native,
+        deadline,
+        condition,
+    } = instruction_type
+    {
+        return handle_initialize(
+            program_id,
+            accounts,
+            price,
+            solana_amount,
+            stablecoin_type,
+            stablecoin_mint,
+            stablecoin_decimals,
+            is_native,
+            deadline,
+            condition,
+        );
     }
-}
 
+    // Every other instruction operates on an already-initialized escrow account,
+    // which is passed as the first account. The remaining accounts are the ones
+    // the individual handlers expect.
+    let escrow_account = &accounts[0];
+    let rest = &accounts[1..];
+    let mut contract = ExchangeContract::load(program_id, escrow_account)?;
 
+    validate_transaction(rest, &contract, &instruction_type)?;
 
+    match instruction_type {
+        InstructionType::Initialize { .. } => unreachable!("handled above"),
+        InstructionType::Deposit { amount } => {
+            let depositor_account = &rest[0];
+            let is_solana = *depositor_account.key == contract.seller_pubkey;
+            handle_deposit(program_id, escrow_account.key, rest, &mut contract, depositor_account.key, amount, is_solana)?;
+        }
+        InstructionType::Exchange => {
+            handle_exchange(program_id, escrow_account.key, rest, &mut contract, &spl_token::id())?;
+        }
+        InstructionType::Refund => {
+            let current_time = Clock::get()?.unix_timestamp;
+            handle_refund(program_id, escrow_account.key, rest, &mut contract, &spl_token::id(), current_time)?;
+        }
+        InstructionType::Cancel => {
+            handle_cancel(program_id, escrow_account.key, rest, &mut contract, &spl_token::id())?;
+        }
+    }
+
+    contract.save(escrow_account)
+}